@@ -1,12 +1,91 @@
 use errors::Result;
+use futures::channel::mpsc::Receiver;
+use futures::stream::Stream;
 use header::Header;
 use hmac::Mac;
+use indexmap::IndexMap;
 use serde::{Serialize as SerdeSerialize, Serializer};
 use serde_derive::Serialize;
-use serde_json::json;
-use std::collections::HashMap;
+use serde_json::{json, Value};
+use std::pin::Pin;
+use std::task::{Context, Poll};
 use wire::WireMessage;
 
+/// A parsed Jupyter messaging-protocol version such as `5.3`.
+///
+/// The version is advertised by the kernel in the `protocol_version` field of
+/// its `kernel_info_reply`; parsing it lets the client adapt message shape and
+/// gate requests the connected kernel does not understand.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ProtocolVersion {
+    pub major: u32,
+    pub minor: u32,
+}
+
+impl ProtocolVersion {
+    /// Parse a dotted version string (`"5.3"`, `"5"`); a missing minor
+    /// component is treated as zero.
+    pub fn parse(s: &str) -> Option<ProtocolVersion> {
+        let mut parts = s.split('.');
+        let major = parts.next()?.parse().ok()?;
+        let minor = match parts.next() {
+            Some(minor) => minor.parse().ok()?,
+            None => 0,
+        };
+        Some(ProtocolVersion { major, minor })
+    }
+
+    /// Extract and parse the `protocol_version` field of a `kernel_info_reply`
+    /// content object.
+    pub fn from_kernel_info(content: &Value) -> Option<ProtocolVersion> {
+        content
+            .get("protocol_version")?
+            .as_str()
+            .and_then(ProtocolVersion::parse)
+    }
+
+    /// Whether a kernel speaking this protocol version understands the given
+    /// request `msg_type`. `history_request` and `is_complete_request` both
+    /// arrived with the 5.0 rewrite of the spec, so they are gated off for
+    /// older kernels. (The `detail_level` field of `inspect_request`, also new
+    /// in 5.0, is adapted separately inside [`Command::into_wire`].)
+    pub fn supports(&self, msg_type: &str) -> bool {
+        let five_oh = ProtocolVersion { major: 5, minor: 0 };
+        match msg_type {
+            "history_request" | "is_complete_request" => *self >= five_oh,
+            _ => true,
+        }
+    }
+}
+
+/// Summary of what a connected kernel supports, produced by negotiating the
+/// protocol version out of its `kernel_info_reply`.
+///
+/// Callers can consult it before sending — e.g. skipping a `history_request`
+/// or `is_complete_request` a pre-5.0 kernel would reject — instead of
+/// assuming a fixed protocol.
+#[derive(Debug, Clone)]
+pub struct Capabilities {
+    pub protocol_version: ProtocolVersion,
+}
+
+impl Capabilities {
+    pub fn new(protocol_version: ProtocolVersion) -> Capabilities {
+        Capabilities { protocol_version }
+    }
+
+    /// Negotiate capabilities from a `kernel_info_reply` content object,
+    /// returning `None` when no usable `protocol_version` is advertised.
+    pub fn from_kernel_info(content: &Value) -> Option<Capabilities> {
+        ProtocolVersion::from_kernel_info(content).map(Capabilities::new)
+    }
+
+    /// Whether the kernel understands the given request `msg_type`.
+    pub fn supports(&self, msg_type: &str) -> bool {
+        self.protocol_version.supports(msg_type)
+    }
+}
+
 #[derive(Serialize, Debug)]
 #[serde(untagged)]
 pub enum Command {
@@ -15,7 +94,10 @@ pub enum Command {
         code: String,
         silent: bool,
         store_history: bool,
-        user_expressions: HashMap<String, String>,
+        // Ordered so expressions evaluate — and serialize — in the order the
+        // caller inserted them, keeping kernel evaluation and test output
+        // deterministic.
+        user_expressions: IndexMap<String, String>,
         allow_stdin: bool,
         stop_on_error: bool,
     },
@@ -40,63 +122,134 @@ pub enum Command {
     Shutdown {
         restart: bool,
     },
+    InputReply {
+        value: String,
+    },
+    CommOpen {
+        comm_id: String,
+        target_name: String,
+        data: Value,
+    },
+    CommMsg {
+        comm_id: String,
+        data: Value,
+    },
+    CommClose {
+        comm_id: String,
+        data: Value,
+    },
 }
 
 impl Command {
-    pub(crate) fn into_wire<M: Mac>(self, auth: M) -> Result<WireMessage<M>> {
+    /// Serialize this command into a [`WireMessage`], signing it with `auth`.
+    ///
+    /// When `parent` is supplied its serialized [`Header`] is placed in the
+    /// `parent_header` slot so the kernel — and any reply correlation the
+    /// caller maintains — can tie this message to an earlier one. The freshly
+    /// generated `msg_id` is returned alongside the wire message so callers can
+    /// key a map from outgoing `msg_id` to a pending response handle and keep
+    /// several requests in flight at once.
+    ///
+    /// `protocol` carries the version negotiated from the kernel's
+    /// `kernel_info_reply` (see [`ProtocolVersion`]); when present it is
+    /// consulted to adapt message shape to the fields that changed across
+    /// protocol revisions.
+    ///
+    /// Large integers and high-precision floats in a comm `data` payload are
+    /// preserved exactly: the crate enables serde_json's `arbitrary_precision`
+    /// feature, so such numbers survive serialization instead of being rounded
+    /// through `f64`.
+    pub(crate) fn into_wire<M: Mac>(
+        self,
+        auth: M,
+        parent: Option<&Header>,
+        protocol: Option<ProtocolVersion>,
+    ) -> Result<(WireMessage<M>, String)> {
+        let parent_header = match parent {
+            Some(header) => header.to_bytes()?.to_vec(),
+            None => b"{}".to_vec(),
+        };
         match self {
             Command::KernelInfo => {
                 let header = Header::new("kernel_info_request");
+                let msg_id = header.msg_id.clone();
                 let header_bytes = header.to_bytes()?;
-                Ok(WireMessage {
+                Ok((WireMessage {
                     header: header_bytes.to_vec(),
-                    parent_header: b"{}".to_vec(),
+                    parent_header: parent_header.clone(),
                     metadata: b"{}".to_vec(),
                     content: b"{}".to_vec(),
                     auth,
-                })
+                }, msg_id))
             }
             r @ Command::Execute { .. } => {
                 let header = Header::new("execute_request");
+                let msg_id = header.msg_id.clone();
                 let header_bytes = header.to_bytes()?;
                 let content_str = serde_json::to_string(&r)?;
                 let content = content_str.into_bytes();
 
-                Ok(WireMessage {
+                Ok((WireMessage {
                     header: header_bytes.to_vec(),
-                    parent_header: b"{}".to_vec(),
+                    parent_header: parent_header.clone(),
                     metadata: b"{}".to_vec(),
                     content,
                     auth,
-                })
+                }, msg_id))
             }
-            r @ Command::Inspect { .. } => {
+            Command::Inspect {
+                code,
+                cursor_pos,
+                detail_level,
+            } => {
                 let header = Header::new("inspect_request");
+                let msg_id = header.msg_id.clone();
                 let header_bytes = header.to_bytes()?;
-                let content_str = serde_json::to_string(&r)?;
+
+                // `detail_level` was introduced with protocol 5.0; older
+                // kernels only ever read `code`/`cursor_pos`, so omit it when
+                // the negotiated version predates it.
+                let pre_five_oh = matches!(
+                    protocol,
+                    Some(version) if version < ProtocolVersion { major: 5, minor: 0 }
+                );
+                let content_json = if pre_five_oh {
+                    json!({
+                        "code": code,
+                        "cursor_pos": cursor_pos,
+                    })
+                } else {
+                    json!({
+                        "code": code,
+                        "cursor_pos": cursor_pos,
+                        "detail_level": detail_level,
+                    })
+                };
+                let content_str = serde_json::to_string(&content_json)?;
                 let content = content_str.into_bytes();
 
-                Ok(WireMessage {
+                Ok((WireMessage {
                     header: header_bytes.to_vec(),
-                    parent_header: b"{}".to_vec(),
+                    parent_header: parent_header.clone(),
                     metadata: b"{}".to_vec(),
                     content,
                     auth,
-                })
+                }, msg_id))
             }
             r @ Command::Complete { .. } => {
                 let header = Header::new("complete_request");
+                let msg_id = header.msg_id.clone();
                 let header_bytes = header.to_bytes()?;
                 let content_str = serde_json::to_string(&r)?;
                 let content = content_str.into_bytes();
 
-                Ok(WireMessage {
+                Ok((WireMessage {
                     header: header_bytes.to_vec(),
-                    parent_header: b"{}".to_vec(),
+                    parent_header: parent_header.clone(),
                     metadata: b"{}".to_vec(),
                     content,
                     auth,
-                })
+                }, msg_id))
             }
             Command::History {
                 output,
@@ -105,6 +258,7 @@ impl Command {
                 unique,
             } => {
                 let header = Header::new("history_request");
+                let msg_id = header.msg_id.clone();
                 let header_bytes = header.to_bytes()?;
 
                 let content = match hist_access_type {
@@ -150,16 +304,17 @@ impl Command {
                 let content_str = serde_json::to_string(&content)?;
                 let content = content_str.into_bytes();
 
-                Ok(WireMessage {
+                Ok((WireMessage {
                     header: header_bytes.to_vec(),
-                    parent_header: b"{}".to_vec(),
+                    parent_header: parent_header.clone(),
                     metadata: b"{}".to_vec(),
                     content,
                     auth,
-                })
+                }, msg_id))
             }
             Command::IsComplete { code } => {
                 let header = Header::new("is_complete_request");
+                let msg_id = header.msg_id.clone();
                 let header_bytes = header.to_bytes()?;
 
                 let content_json = json!({
@@ -168,16 +323,97 @@ impl Command {
                 let content_str = serde_json::to_string(&content_json)?;
                 let content = content_str.into_bytes();
 
-                Ok(WireMessage {
+                Ok((WireMessage {
                     header: header_bytes.to_vec(),
-                    parent_header: b"{}".to_vec(),
+                    parent_header: parent_header.clone(),
                     metadata: b"{}".to_vec(),
                     content: content,
                     auth,
-                })
+                }, msg_id))
+            }
+            Command::InputReply { value } => {
+                let header = Header::new("input_reply");
+                let msg_id = header.msg_id.clone();
+                let header_bytes = header.to_bytes()?;
+                let content_json = json!({
+                    "value": value,
+                });
+                let content_str = serde_json::to_string(&content_json)?;
+                let content = content_str.into_bytes();
+
+                Ok((WireMessage {
+                    header: header_bytes.to_vec(),
+                    parent_header: parent_header.clone(),
+                    metadata: b"{}".to_vec(),
+                    content,
+                    auth,
+                }, msg_id))
+            }
+            Command::CommOpen {
+                comm_id,
+                target_name,
+                data,
+            } => {
+                let header = Header::new("comm_open");
+                let msg_id = header.msg_id.clone();
+                let header_bytes = header.to_bytes()?;
+                let content_json = json!({
+                    "comm_id": comm_id,
+                    "target_name": target_name,
+                    "data": data,
+                });
+                let content_str = serde_json::to_string(&content_json)?;
+                let content = content_str.into_bytes();
+
+                Ok((WireMessage {
+                    header: header_bytes.to_vec(),
+                    parent_header: parent_header.clone(),
+                    metadata: b"{}".to_vec(),
+                    content,
+                    auth,
+                }, msg_id))
+            }
+            Command::CommMsg { comm_id, data } => {
+                let header = Header::new("comm_msg");
+                let msg_id = header.msg_id.clone();
+                let header_bytes = header.to_bytes()?;
+                let content_json = json!({
+                    "comm_id": comm_id,
+                    "data": data,
+                });
+                let content_str = serde_json::to_string(&content_json)?;
+                let content = content_str.into_bytes();
+
+                Ok((WireMessage {
+                    header: header_bytes.to_vec(),
+                    parent_header: parent_header.clone(),
+                    metadata: b"{}".to_vec(),
+                    content,
+                    auth,
+                }, msg_id))
+            }
+            Command::CommClose { comm_id, data } => {
+                let header = Header::new("comm_close");
+                let msg_id = header.msg_id.clone();
+                let header_bytes = header.to_bytes()?;
+                let content_json = json!({
+                    "comm_id": comm_id,
+                    "data": data,
+                });
+                let content_str = serde_json::to_string(&content_json)?;
+                let content = content_str.into_bytes();
+
+                Ok((WireMessage {
+                    header: header_bytes.to_vec(),
+                    parent_header: parent_header.clone(),
+                    metadata: b"{}".to_vec(),
+                    content,
+                    auth,
+                }, msg_id))
             }
             Command::Shutdown { restart } => {
                 let header = Header::new("shutdown_request");
+                let msg_id = header.msg_id.clone();
                 let header_bytes = header.to_bytes()?;
                 let content_json = json!({
                     "restart": restart,
@@ -185,13 +421,139 @@ impl Command {
                 let content_str = serde_json::to_string(&content_json)?;
                 let content = content_str.into_bytes();
 
-                Ok(WireMessage {
+                Ok((WireMessage {
                     header: header_bytes.to_vec(),
-                    parent_header: b"{}".to_vec(),
+                    parent_header: parent_header.clone(),
                     metadata: b"{}".to_vec(),
                     content,
                     auth,
-                })
+                }, msg_id))
+            }
+        }
+    }
+}
+
+/// A prompt a kernel sends on the stdin channel when executing code that
+/// blocks on `input()` / `raw_input()` with `allow_stdin: true`.
+#[derive(Debug, Clone)]
+pub struct InputRequest {
+    pub prompt: String,
+    pub password: bool,
+}
+
+impl InputRequest {
+    /// Decode the content object of an `input_request` message.
+    pub fn from_content(content: &Value) -> InputRequest {
+        InputRequest {
+            prompt: content
+                .get("prompt")
+                .and_then(Value::as_str)
+                .unwrap_or("")
+                .to_owned(),
+            password: content
+                .get("password")
+                .and_then(Value::as_bool)
+                .unwrap_or(false),
+        }
+    }
+
+    /// Build the [`Command::InputReply`] answering this request with `value`.
+    pub fn reply(&self, value: String) -> Command {
+        Command::InputReply { value }
+    }
+}
+
+/// Supplies values in response to `input_request`s on the stdin channel.
+pub trait InputProvider {
+    fn provide(&mut self, request: &InputRequest) -> String;
+}
+
+/// Route a decoded stdin-channel message back to a reply. When `message` is an
+/// `input_request`, `provider` is asked for a value and the resulting
+/// [`Command::InputReply`] — to be sent on the stdin channel — is returned;
+/// any other message type yields `None`. This closes the loop that makes
+/// `allow_stdin: true` usable for interactive programs.
+pub fn route_stdin<P: InputProvider>(message: &Value, provider: &mut P) -> Option<Command> {
+    let msg_type = message.get("header")?.get("msg_type")?.as_str()?;
+    if msg_type != "input_request" {
+        return None;
+    }
+    let content = message.get("content").cloned().unwrap_or_else(|| json!({}));
+    let request = InputRequest::from_content(&content);
+    let value = provider.provide(&request);
+    Some(request.reply(value))
+}
+
+/// An async stream of decoded kernel replies correlated to a single request.
+///
+/// Hand [`ReplyStream::new`] the `msg_id` returned by [`Command::into_wire`]
+/// together with a receiver fed every decoded message (a full Jupyter message
+/// object, `parent_header`/`content` included) off the shell and iopub
+/// channels. The stream yields only messages whose `parent_header.msg_id`
+/// matches the request and completes once the kernel reports `status: idle`
+/// for it, so incremental output from a long-running cell can be consumed with
+/// a plain `while let Some(msg) = stream.next().await` instead of ad-hoc
+/// polling.
+pub struct ReplyStream {
+    msg_id: String,
+    incoming: Receiver<Value>,
+    done: bool,
+}
+
+impl ReplyStream {
+    pub fn new(msg_id: String, incoming: Receiver<Value>) -> ReplyStream {
+        ReplyStream {
+            msg_id,
+            incoming,
+            done: false,
+        }
+    }
+}
+
+/// The `parent_header.msg_id` of a decoded message, if present.
+fn parent_msg_id(message: &Value) -> Option<&str> {
+    message
+        .get("parent_header")?
+        .get("msg_id")?
+        .as_str()
+}
+
+/// Whether a decoded message is the `status: idle` transition that closes a
+/// request.
+fn is_idle(message: &Value) -> bool {
+    message
+        .get("content")
+        .and_then(|content| content.get("execution_state"))
+        .and_then(Value::as_str)
+        == Some("idle")
+}
+
+impl Stream for ReplyStream {
+    type Item = Value;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Value>> {
+        let this = self.get_mut();
+        if this.done {
+            return Poll::Ready(None);
+        }
+        loop {
+            match Pin::new(&mut this.incoming).poll_next(cx) {
+                Poll::Ready(Some(message)) => {
+                    if parent_msg_id(&message) != Some(this.msg_id.as_str()) {
+                        continue;
+                    }
+                    // The idle status closes the request; yield it, then the
+                    // next poll terminates the stream.
+                    if is_idle(&message) {
+                        this.done = true;
+                    }
+                    return Poll::Ready(Some(message));
+                }
+                Poll::Ready(None) => {
+                    this.done = true;
+                    return Poll::Ready(None);
+                }
+                Poll::Pending => return Poll::Pending,
             }
         }
     }
@@ -221,3 +583,106 @@ impl SerdeSerialize for DetailLevel {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_major_and_minor() {
+        assert_eq!(
+            ProtocolVersion::parse("5.3"),
+            Some(ProtocolVersion { major: 5, minor: 3 })
+        );
+    }
+
+    #[test]
+    fn treats_missing_minor_as_zero() {
+        assert_eq!(
+            ProtocolVersion::parse("5"),
+            Some(ProtocolVersion { major: 5, minor: 0 })
+        );
+    }
+
+    #[test]
+    fn rejects_non_numeric_version() {
+        assert_eq!(ProtocolVersion::parse("five.three"), None);
+        assert_eq!(ProtocolVersion::parse(""), None);
+    }
+
+    #[test]
+    fn gates_5_0_only_messages_for_older_kernels() {
+        let old = ProtocolVersion { major: 4, minor: 1 };
+        let new = ProtocolVersion { major: 5, minor: 3 };
+
+        assert!(!old.supports("history_request"));
+        assert!(!old.supports("is_complete_request"));
+        assert!(old.supports("execute_request"));
+
+        assert!(new.supports("history_request"));
+        assert!(new.supports("is_complete_request"));
+    }
+
+    struct CannedInput(&'static str);
+
+    impl InputProvider for CannedInput {
+        fn provide(&mut self, _request: &InputRequest) -> String {
+            self.0.to_owned()
+        }
+    }
+
+    #[test]
+    fn routes_input_request_to_reply() {
+        let message = json!({
+            "header": { "msg_type": "input_request" },
+            "content": { "prompt": "Name: ", "password": false },
+        });
+        match route_stdin(&message, &mut CannedInput("ada")) {
+            Some(Command::InputReply { value }) => assert_eq!(value, "ada"),
+            other => panic!("expected InputReply, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn ignores_non_input_request_on_stdin() {
+        let message = json!({ "header": { "msg_type": "status" } });
+        assert!(route_stdin(&message, &mut CannedInput("x")).is_none());
+    }
+
+    #[test]
+    fn extracts_parent_msg_id() {
+        let message = json!({ "parent_header": { "msg_id": "abc-123" } });
+        assert_eq!(parent_msg_id(&message), Some("abc-123"));
+
+        let orphan = json!({ "parent_header": {} });
+        assert_eq!(parent_msg_id(&orphan), None);
+    }
+
+    #[test]
+    fn detects_idle_status_transition() {
+        let idle = json!({ "content": { "execution_state": "idle" } });
+        assert!(is_idle(&idle));
+
+        let busy = json!({ "content": { "execution_state": "busy" } });
+        assert!(!is_idle(&busy));
+
+        let other = json!({ "content": { "data": {} } });
+        assert!(!is_idle(&other));
+    }
+
+    #[test]
+    fn negotiates_version_from_kernel_info_reply() {
+        let content = json!({ "protocol_version": "5.3" });
+        assert_eq!(
+            ProtocolVersion::from_kernel_info(&content),
+            Some(ProtocolVersion { major: 5, minor: 3 })
+        );
+
+        let capabilities = Capabilities::from_kernel_info(&content).unwrap();
+        assert!(capabilities.supports("history_request"));
+
+        let missing = json!({});
+        assert_eq!(ProtocolVersion::from_kernel_info(&missing), None);
+        assert!(Capabilities::from_kernel_info(&missing).is_none());
+    }
+}